@@ -1,51 +1,212 @@
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::error::Error;
 use std::sync::Arc;
+use std::time::Instant;
 
 use actix_cors::Cors;
 use actix_web::{web, App, HttpResponse, HttpServer, Result, middleware};
 use actix_ws;
-use bluest::{btuuid::bluetooth_uuid_from_u16, Adapter, Device, Uuid};
+use bluest::{btuuid::bluetooth_uuid_from_u16, Adapter, Device, DeviceId, Uuid};
 use futures_lite::stream::StreamExt;
 use serde::{Deserialize, Serialize};
-use tokio::sync::{RwLock, broadcast};
+use tokio::sync::{mpsc, watch, RwLock, broadcast};
 use tokio::time::{sleep, Duration};
 use actix_web::rt::spawn;
 
 // Define heart rate data structure
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HeartRateData {
+    pub device_id: String,
+    pub device_name: Option<String>,
     pub value: u16,
     pub sensor_contact_detected: Option<bool>,
+    pub energy_expended: Option<u16>,
+    pub rr_intervals: Vec<f32>,
+    pub battery_level: Option<u8>,
     pub timestamp: u64,
 }
 
+// Rolling HRV summary for a single device, as returned by `/api/hrv`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HrvData {
+    pub device_id: String,
+    pub device_name: Option<String>,
+    pub rmssd_ms: Option<f32>,
+    pub sample_count: usize,
+}
+
+// Summary of a tracked device, as returned by `/api/devices`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceSummary {
+    pub device_id: String,
+    pub device_name: Option<String>,
+    pub timestamp: u64,
+}
+
+// Connection lifecycle of a single device, as tracked for `/api/status`
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    Disconnected,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceStatus {
+    pub device_id: String,
+    pub device_name: Option<String>,
+    pub state: ConnectionState,
+}
+
+// Overall monitor status, as returned by `/api/status`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MonitorStatus {
+    pub scanning: bool,
+    pub devices: Vec<DeviceStatus>,
+}
+
+// A device we've successfully connected to before, persisted so future restarts can
+// reconnect directly instead of waiting for a fresh scan to find it again.
+//
+// `bluest::DeviceId` only implements `Serialize`/`Deserialize` when bluest's own
+// `serde` cargo feature is enabled, which nothing in this workspace turns on. Rather
+// than depend on a feature of a type we don't control, we persist the `Display` form
+// of the id and re-resolve it against freshly discovered devices in
+// `reconnect_known_devices`, the same way `/api/connect/{device_id}` resolves a
+// scanned device id string back to a live `Device`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct KnownDevice {
+    id: String,
+    name: Option<String>,
+}
+
+// Battery level for a single device, as returned by `/api/battery`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatteryStatus {
+    pub device_id: String,
+    pub device_name: Option<String>,
+    pub battery_level: u8,
+}
+
+// A single advertising device found by `/api/scan`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScanResult {
+    pub device_id: String,
+    pub device_name: Option<String>,
+    pub rssi: Option<i16>,
+}
+
 // Global state management
 #[derive(Clone)]
 pub struct AppState {
-    pub heart_rate_data: Arc<RwLock<Option<HeartRateData>>>,
+    pub heart_rate_data: Arc<RwLock<HashMap<String, HeartRateData>>>,
+    pub hrv_windows: Arc<RwLock<HashMap<String, VecDeque<f32>>>>,
+    pub device_status: Arc<RwLock<HashMap<String, DeviceStatus>>>,
+    pub battery_levels: Arc<RwLock<HashMap<String, u8>>>,
+    pub scanning: Arc<RwLock<bool>>,
+    pub battery_warn_threshold: u8,
+    pub ws_heartbeat_interval: Duration,
+    pub ws_heartbeat_timeout: Duration,
+    // Devices seen by the most recent `/api/scan`, so `/api/connect/{device_id}` can
+    // resolve the plain device id string back into a real `DeviceId`.
+    pub last_scan: Arc<RwLock<HashMap<String, DeviceId>>>,
+    // Lets a running `handle_device` notify loop be told to stop, keyed by device id.
+    // Used by `/api/connect/{device_id}` to tear down whatever device(s) the scanner
+    // auto-selected before binding to the user's choice. A `watch` channel holds the
+    // stop request durably so it can't be missed by a task that isn't awaiting it at
+    // the exact moment the signal fires, unlike `Notify::notify_waiters`.
+    pub device_stop_signals: Arc<RwLock<HashMap<String, Arc<watch::Sender<bool>>>>>,
+    // Serializes read-modify-write access to `known_devices.json`, since every device's
+    // `handle_device` task calls `remember_known_device` independently.
+    pub known_devices_lock: Arc<tokio::sync::Mutex<()>>,
+    pub connect_requests: mpsc::UnboundedSender<DeviceId>,
+    pub adapter: Adapter,
     pub tx: broadcast::Sender<HeartRateData>,
 }
 
 const HRS_UUID: Uuid = bluetooth_uuid_from_u16(0x180D);
 const HRM_UUID: Uuid = bluetooth_uuid_from_u16(0x2A37);
+const BATTERY_SERVICE_UUID: Uuid = bluetooth_uuid_from_u16(0x180F);
+const BATTERY_LEVEL_UUID: Uuid = bluetooth_uuid_from_u16(0x2A19);
+
+// Number of RR-intervals kept per device for the rolling RMSSD calculation
+const HRV_WINDOW_SIZE: usize = 60;
+
+// Where we persist the devices we've successfully connected to before
+const KNOWN_DEVICES_PATH: &str = "known_devices.json";
+
+// Backoff bounds for reconnect attempts after a device drops
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+// How often to poll battery level when the characteristic doesn't support notifications
+const BATTERY_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+// Default battery-low warning threshold, overridable via BATTERY_WARN_THRESHOLD_PERCENT
+const DEFAULT_BATTERY_WARN_THRESHOLD: u8 = 20;
+
+// Default WebSocket heartbeat cadence, overridable via WS_HEARTBEAT_INTERVAL_SECS / WS_HEARTBEAT_TIMEOUT_SECS
+const DEFAULT_WS_HEARTBEAT_INTERVAL_SECS: u64 = 5;
+const DEFAULT_WS_HEARTBEAT_TIMEOUT_SECS: u64 = 10;
+
+// How long a bounded device-discovery scan listens for advertisements before giving up
+const SCAN_WINDOW: Duration = Duration::from_secs(5);
 
 #[actix_web::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     env_logger::init();
 
+    let adapter = Adapter::default().await.ok_or("Bluetooth adapter not found")?;
+    adapter.wait_available().await?;
+
     // Create broadcast channel for WebSocket push
     let (tx, _) = broadcast::channel::<HeartRateData>(100);
 
+    // Channel the `/api/connect/{device_id}` handler uses to tell the monitor task to
+    // connect to a specific, user-selected device
+    let (connect_tx, connect_rx) = mpsc::unbounded_channel::<DeviceId>();
+
+    let battery_warn_threshold = std::env::var("BATTERY_WARN_THRESHOLD_PERCENT")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_BATTERY_WARN_THRESHOLD);
+
+    let ws_heartbeat_interval = Duration::from_secs(
+        std::env::var("WS_HEARTBEAT_INTERVAL_SECS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_WS_HEARTBEAT_INTERVAL_SECS),
+    );
+    let ws_heartbeat_timeout = Duration::from_secs(
+        std::env::var("WS_HEARTBEAT_TIMEOUT_SECS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_WS_HEARTBEAT_TIMEOUT_SECS),
+    );
+
     // Create global state
     let app_state = AppState {
-        heart_rate_data: Arc::new(RwLock::new(None)),
+        heart_rate_data: Arc::new(RwLock::new(HashMap::new())),
+        hrv_windows: Arc::new(RwLock::new(HashMap::new())),
+        device_status: Arc::new(RwLock::new(HashMap::new())),
+        battery_levels: Arc::new(RwLock::new(HashMap::new())),
+        scanning: Arc::new(RwLock::new(false)),
+        battery_warn_threshold,
+        ws_heartbeat_interval,
+        ws_heartbeat_timeout,
+        last_scan: Arc::new(RwLock::new(HashMap::new())),
+        device_stop_signals: Arc::new(RwLock::new(HashMap::new())),
+        known_devices_lock: Arc::new(tokio::sync::Mutex::new(())),
+        connect_requests: connect_tx,
+        adapter,
         tx,
     };
 
     // Start Bluetooth monitoring task
     let state_clone = app_state.clone();
     spawn(async move {
-        if let Err(e) = start_bluetooth_monitor(state_clone).await {
+        if let Err(e) = start_bluetooth_monitor(state_clone, connect_rx).await {
             log::error!("Bluetooth monitoring failed: {:?}", e);
         }
     });
@@ -57,6 +218,13 @@ async fn main() -> Result<(), Box<dyn Error>> {
             .wrap(cors_config())
             .wrap(middleware::Logger::default())
             .route("/api/heart-rate", web::get().to(get_heart_rate))
+            .route("/api/heart-rate/{device_id}", web::get().to(get_heart_rate_for_device))
+            .route("/api/devices", web::get().to(get_devices))
+            .route("/api/hrv", web::get().to(get_hrv))
+            .route("/api/status", web::get().to(get_status))
+            .route("/api/battery", web::get().to(get_battery))
+            .route("/api/scan", web::get().to(scan_devices))
+            .route("/api/connect/{device_id}", web::post().to(connect_device))
             .route("/api/ws", web::get().to(ws_handler))
             .service(actix_files::Files::new("/", "./static").index_file("index.html"))
     })
@@ -75,10 +243,15 @@ fn cors_config() -> Cors {
         .supports_credentials()
 }
 
-// API endpoint to get current heart rate data
+// Picks the most recently updated device, for callers that don't care which device they get
+fn most_recent(devices: &HashMap<String, HeartRateData>) -> Option<HeartRateData> {
+    devices.values().max_by_key(|data| data.timestamp).cloned()
+}
+
+// API endpoint to get the most recently updated heart rate reading
 async fn get_heart_rate(data: web::Data<AppState>) -> Result<HttpResponse> {
-    let heart_rate = data.heart_rate_data.read().await.clone();
-    
+    let heart_rate = most_recent(&*data.heart_rate_data.read().await);
+
     match heart_rate {
         Some(hr_data) => {
             Ok(HttpResponse::Ok()
@@ -93,6 +266,240 @@ async fn get_heart_rate(data: web::Data<AppState>) -> Result<HttpResponse> {
     }
 }
 
+// API endpoint to get the heart rate reading for a specific device
+async fn get_heart_rate_for_device(
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let device_id = path.into_inner();
+    let heart_rate = data.heart_rate_data.read().await.get(&device_id).cloned();
+
+    match heart_rate {
+        Some(hr_data) => {
+            Ok(HttpResponse::Ok()
+                .content_type("application/json")
+                .json(hr_data))
+        },
+        None => {
+            Ok(HttpResponse::NotFound()
+                .content_type("application/json")
+                .json(serde_json::json!({"error": "No heart rate data available for this device"})))
+        }
+    }
+}
+
+// API endpoint listing every device that has reported a heart rate reading, including
+// devices whose connection has since ended
+async fn get_devices(data: web::Data<AppState>) -> Result<HttpResponse> {
+    let devices: Vec<DeviceSummary> = data
+        .heart_rate_data
+        .read()
+        .await
+        .values()
+        .map(|hr_data| DeviceSummary {
+            device_id: hr_data.device_id.clone(),
+            device_name: hr_data.device_name.clone(),
+            timestamp: hr_data.timestamp,
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(devices))
+}
+
+// API endpoint exposing the rolling RMSSD HRV metric for every tracked device
+async fn get_hrv(data: web::Data<AppState>) -> Result<HttpResponse> {
+    let heart_rate_data = data.heart_rate_data.read().await;
+    let hrv_windows = data.hrv_windows.read().await;
+
+    let hrv: Vec<HrvData> = hrv_windows
+        .iter()
+        .map(|(device_id, window)| HrvData {
+            device_id: device_id.clone(),
+            device_name: heart_rate_data.get(device_id).and_then(|d| d.device_name.clone()),
+            rmssd_ms: rmssd(window),
+            sample_count: window.len(),
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(hrv))
+}
+
+// Root mean square of successive RR-interval differences, the standard short-term HRV metric
+fn rmssd(window: &VecDeque<f32>) -> Option<f32> {
+    if window.len() < 2 {
+        return None;
+    }
+
+    let mut sum_of_squares = 0.0f32;
+    let mut count = 0u32;
+    for (a, b) in window.iter().zip(window.iter().skip(1)) {
+        let diff = b - a;
+        sum_of_squares += diff * diff;
+        count += 1;
+    }
+
+    Some((sum_of_squares / count as f32).sqrt())
+}
+
+// API endpoint exposing whether the monitor is scanning and each device's connection state
+async fn get_status(data: web::Data<AppState>) -> Result<HttpResponse> {
+    let status = MonitorStatus {
+        scanning: *data.scanning.read().await,
+        devices: data.device_status.read().await.values().cloned().collect(),
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(status))
+}
+
+// API endpoint listing the latest known battery level for every tracked device
+async fn get_battery(data: web::Data<AppState>) -> Result<HttpResponse> {
+    let heart_rate_data = data.heart_rate_data.read().await;
+    let battery_levels = data.battery_levels.read().await;
+
+    let battery: Vec<BatteryStatus> = battery_levels
+        .iter()
+        .map(|(device_id, &battery_level)| BatteryStatus {
+            device_id: device_id.clone(),
+            device_name: heart_rate_data.get(device_id).and_then(|d| d.device_name.clone()),
+            battery_level,
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(battery))
+}
+
+// API endpoint that scans for nearby HRS-capable devices for a bounded window and
+// reports their id, name and RSSI so a user can pick which one to connect to.
+async fn scan_devices(data: web::Data<AppState>) -> Result<HttpResponse> {
+    let mut scan = match data.adapter.discover_devices(&[HRS_UUID]).await {
+        Ok(scan) => scan,
+        Err(err) => {
+            return Ok(HttpResponse::InternalServerError()
+                .content_type("application/json")
+                .json(serde_json::json!({"error": format!("Failed to start scan: {err:?}")})));
+        }
+    };
+
+    // Keyed by device id so the same device re-advertising during the scan window
+    // updates its entry in place instead of appearing multiple times in the results;
+    // bluest's own docs note that duplicate advertisements are a platform detail.
+    let mut found: HashMap<String, (DeviceId, ScanResult)> = HashMap::new();
+    let window = sleep(SCAN_WINDOW);
+    tokio::pin!(window);
+
+    loop {
+        tokio::select! {
+            device = scan.next() => {
+                let Some(Ok(device)) = device else { break };
+                let device_id = device.id().to_string();
+                let device_name = device.name_async().await.ok();
+                let rssi = device.rssi().await.ok();
+                found.insert(
+                    device_id.clone(),
+                    (device.id(), ScanResult { device_id, device_name, rssi }),
+                );
+            }
+            _ = &mut window => break,
+        }
+    }
+
+    *data.last_scan.write().await = found
+        .iter()
+        .map(|(device_id, (id, _))| (device_id.clone(), id.clone()))
+        .collect();
+    let results: Vec<ScanResult> = found.into_values().map(|(_, result)| result).collect();
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(results))
+}
+
+// API endpoint that tells the monitor task to connect to a device found by `/api/scan`
+async fn connect_device(path: web::Path<String>, data: web::Data<AppState>) -> Result<HttpResponse> {
+    let device_id = path.into_inner();
+    let id = data.last_scan.read().await.get(&device_id).cloned();
+
+    match id {
+        Some(id) => {
+            let _ = data.connect_requests.send(id);
+            Ok(HttpResponse::Accepted()
+                .content_type("application/json")
+                .json(serde_json::json!({"status": "connecting", "device_id": device_id})))
+        }
+        None => Ok(HttpResponse::NotFound()
+            .content_type("application/json")
+            .json(serde_json::json!({"error": "Unknown device id; run /api/scan first"}))),
+    }
+}
+
+// Updates the tracked connection state for a single device
+async fn set_device_status(
+    app_state: &AppState,
+    device_id: &str,
+    device_name: Option<String>,
+    state: ConnectionState,
+) {
+    app_state.device_status.write().await.insert(
+        device_id.to_string(),
+        DeviceStatus {
+            device_id: device_id.to_string(),
+            device_name,
+            state,
+        },
+    );
+}
+
+// Loads the devices we've successfully connected to in previous runs
+fn load_known_devices() -> Vec<KnownDevice> {
+    std::fs::read_to_string(KNOWN_DEVICES_PATH)
+        .ok()
+        .and_then(|contents| serde_json::from_str(&contents).ok())
+        .unwrap_or_default()
+}
+
+// Records a device as known so future restarts can reconnect directly to it. Holds
+// `known_devices_lock` across the whole read-modify-write so two devices finishing
+// their first connection around the same time can't clobber each other's entry.
+async fn remember_known_device(app_state: &AppState, device: &Device, device_name: Option<String>) {
+    let _guard = app_state.known_devices_lock.lock().await;
+
+    let mut known = load_known_devices();
+    let id = device.id().to_string();
+    if known.iter().any(|d| d.id == id) {
+        return;
+    }
+
+    known.push(KnownDevice { id, name: device_name });
+    match serde_json::to_string_pretty(&known) {
+        Ok(json) => {
+            if let Err(err) = std::fs::write(KNOWN_DEVICES_PATH, json) {
+                println!("Failed to persist known devices: {err:?}");
+            }
+        }
+        Err(err) => println!("Failed to serialize known devices: {err:?}"),
+    }
+}
+
+// Folds freshly parsed RR-intervals into a device's sliding HRV window
+async fn record_rr_intervals(app_state: &AppState, device_id: &str, rr_intervals: &[f32]) {
+    let mut windows = app_state.hrv_windows.write().await;
+    let window = windows.entry(device_id.to_string()).or_default();
+    for &rr in rr_intervals {
+        window.push_back(rr);
+        while window.len() > HRV_WINDOW_SIZE {
+            window.pop_front();
+        }
+    }
+}
+
 // WebSocket处理器
 async fn ws_handler(
     req: actix_web::HttpRequest,
@@ -104,9 +511,9 @@ async fn ws_handler(
     // Subscribe to broadcast channel
     let mut rx = data.tx.subscribe();
 
-    // Send current heart rate data to newly connected clients
+    // Send the most recently updated device's data to newly connected clients
     {
-        let current_data = data.heart_rate_data.read().await.clone();
+        let current_data = most_recent(&*data.heart_rate_data.read().await);
         if let Some(hr_data) = current_data {
             let _ = session.text(serde_json::to_string(&hr_data).unwrap()).await;
         }
@@ -116,20 +523,28 @@ async fn ws_handler(
     let session_closed = Arc::new(tokio::sync::Notify::new());
     let session_closed_clone = session_closed.clone();
 
+    // Tracks the last time we heard anything from the client, for the heartbeat task below
+    let last_activity = Arc::new(RwLock::new(Instant::now()));
+
     // Start message handling task
     let mut session_for_msg = session.clone();
+    let last_activity_for_msg = last_activity.clone();
     actix_web::rt::spawn(async move {
         let mut msg_stream = msg_stream;
-        
+
         while let Some(msg) = msg_stream.next().await {
             match msg {
                 Ok(actix_ws::Message::Ping(bytes)) => {
+                    *last_activity_for_msg.write().await = Instant::now();
                     if session_for_msg.pong(&bytes).await.is_err() {
                         break;
                     }
                 }
-                Ok(actix_ws::Message::Pong(_)) => {}
+                Ok(actix_ws::Message::Pong(_)) => {
+                    *last_activity_for_msg.write().await = Instant::now();
+                }
                 Ok(actix_ws::Message::Text(text)) => {
+                    *last_activity_for_msg.write().await = Instant::now();
                     // Respond to simple ping message
                     if text == "ping" {
                         if session_for_msg.text("pong").await.is_err() {
@@ -146,6 +561,30 @@ async fn ws_handler(
         session_closed_clone.notify_waiters(); // Notify other tasks that connection is closed
     });
 
+    // Start heartbeat task: pings the client on a fixed interval and closes the session
+    // if nothing has been heard back within the configured timeout.
+    let mut session_for_heartbeat = session.clone();
+    let closed_for_heartbeat = session_closed.clone();
+    let heartbeat_interval = data.ws_heartbeat_interval;
+    let heartbeat_timeout = data.ws_heartbeat_timeout;
+    actix_web::rt::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = sleep(heartbeat_interval) => {
+                    if last_activity.read().await.elapsed() > heartbeat_timeout {
+                        let _ = session_for_heartbeat.close(None).await;
+                        break;
+                    }
+                    if session_for_heartbeat.ping(b"").await.is_err() {
+                        break;
+                    }
+                }
+                _ = closed_for_heartbeat.notified() => break,
+            }
+        }
+        closed_for_heartbeat.notify_waiters(); // Make sure the other tasks exit too
+    });
+
     // Start message forwarding task
     let mut session_for_broadcasts = session;
     let closed_notifier = session_closed.clone();
@@ -169,104 +608,476 @@ async fn ws_handler(
     Ok(response)
 }
 
-// Start Bluetooth monitoring task
-async fn start_bluetooth_monitor(app_state: AppState) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let adapter = Adapter::default()
-        .await
-        .ok_or("Bluetooth adapter not found")?;
-    adapter.wait_available().await.map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
+// Start Bluetooth monitoring task: discovers every HRS-capable device and spawns
+// one `handle_device` notify loop per device, rather than locking onto the first match.
+async fn start_bluetooth_monitor(
+    app_state: AppState,
+    mut connect_requests: mpsc::UnboundedReceiver<DeviceId>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let adapter = app_state.adapter.clone();
+
+    // Devices we've already spawned a handler for, so we don't start a second
+    // notify loop for the same device on every discovery pass.
+    let mut tracked_devices: HashSet<String> = HashSet::new();
+
+    // Try reconnecting directly to devices we've connected to before, rather than
+    // waiting for a fresh scan to find them again.
+    reconnect_known_devices(&adapter, &app_state, &mut tracked_devices).await;
 
     loop {
-        let device = {
-            let connected_heart_rate_devices =
-                adapter.connected_devices_with_services(&[HRS_UUID]).await
-                    .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
-            if let Some(device) = connected_heart_rate_devices.into_iter().next() {
-                device
-            } else {
-                println!("Ready to scan");
-                let mut scan = adapter.discover_devices(&[HRS_UUID]).await
-                    .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
-
-                println!("Scanning devices");
-                let device = scan.next().await
-                    .ok_or("No device found")?
-                    .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
-
-                println!("Found Device: [{}] {:?}", device, device.name_async().await);
-                device
+        // A user picking a device via `/api/connect/{device_id}` takes priority over
+        // whatever this pass would otherwise have discovered.
+        while let Ok(id) = connect_requests.try_recv() {
+            connect_to_selected_device(&adapter, id, &app_state, &mut tracked_devices).await;
+        }
+
+        let connected_heart_rate_devices =
+            adapter.connected_devices_with_services(&[HRS_UUID]).await
+                .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
+
+        if connected_heart_rate_devices.is_empty() {
+            println!("Ready to scan");
+            *app_state.scanning.write().await = true;
+            let mut scan = adapter.discover_devices(&[HRS_UUID]).await
+                .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
+
+            println!("Scanning devices");
+            // Bounded like `scan_devices`/`reconnect_known_devices`, and raced against
+            // `connect_requests` so a `/api/connect/{device_id}` call isn't stuck behind
+            // an empty scan window.
+            let window = sleep(SCAN_WINDOW);
+            tokio::pin!(window);
+
+            loop {
+                tokio::select! {
+                    device = scan.next() => {
+                        let Some(device) = device else { break };
+                        let device = device.map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
+                        println!("Found Device: [{}] {:?}", device, device.name_async().await);
+                        spawn_device_handler(&adapter, device, &app_state, &mut tracked_devices).await;
+                    }
+                    _ = &mut window => break,
+                    Some(id) = connect_requests.recv() => {
+                        connect_to_selected_device(&adapter, id, &app_state, &mut tracked_devices).await;
+                    }
+                }
             }
-        };
+            *app_state.scanning.write().await = false;
+        } else {
+            for device in connected_heart_rate_devices {
+                spawn_device_handler(&adapter, device, &app_state, &mut tracked_devices).await;
+            }
+        }
 
-        if let Err(err) = handle_device(&adapter, &device, &app_state).await {
-            println!("Connection error: {err:?}");
-            // Wait for a period before retrying
-            sleep(Duration::from_secs(5)).await;
+        tokio::select! {
+            _ = sleep(Duration::from_secs(5)) => {}
+            Some(id) = connect_requests.recv() => {
+                connect_to_selected_device(&adapter, id, &app_state, &mut tracked_devices).await;
+            }
+        }
+    }
+}
+
+// Scans for a bounded window looking for any of our persisted known devices, so they
+// can be reconnected directly on startup without waiting for `/api/scan` to find them.
+async fn reconnect_known_devices(
+    adapter: &Adapter,
+    app_state: &AppState,
+    tracked_devices: &mut HashSet<String>,
+) {
+    let known_ids: HashSet<String> = load_known_devices().into_iter().map(|d| d.id).collect();
+    if known_ids.is_empty() {
+        return;
+    }
+
+    let mut scan = match adapter.discover_devices(&[HRS_UUID]).await {
+        Ok(scan) => scan,
+        Err(err) => {
+            println!("Could not scan for known devices: {err:?}");
+            return;
         }
+    };
+
+    let window = sleep(SCAN_WINDOW);
+    tokio::pin!(window);
+
+    loop {
+        tokio::select! {
+            device = scan.next() => {
+                let Some(Ok(device)) = device else { break };
+                if known_ids.contains(&device.id().to_string()) {
+                    spawn_device_handler(adapter, device, app_state, tracked_devices).await;
+                }
+            }
+            _ = &mut window => break,
+        }
+    }
+}
+
+// Opens a direct connection to a device chosen via `/api/connect/{device_id}`. Stops
+// whatever device(s) the scanner had already latched onto, so the user's selection is
+// the only one streaming afterwards.
+async fn connect_to_selected_device(
+    adapter: &Adapter,
+    id: DeviceId,
+    app_state: &AppState,
+    tracked_devices: &mut HashSet<String>,
+) {
+    let selected_id = id.to_string();
+    println!("Connecting to user-selected device: {id}");
+
+    stop_other_devices(app_state, tracked_devices, &selected_id).await;
+
+    match adapter.open_device(&id).await {
+        Ok(device) => spawn_device_handler(adapter, device, app_state, tracked_devices).await,
+        Err(err) => println!("Could not connect to selected device {id}: {err:?}"),
+    }
+}
+
+// Signals every currently tracked device other than `keep` to stop, so a user
+// selection via `/api/connect/{device_id}` deterministically wins over whatever the
+// scanner found first.
+async fn stop_other_devices(app_state: &AppState, tracked_devices: &mut HashSet<String>, keep: &str) {
+    let others: Vec<(String, Arc<watch::Sender<bool>>)> = app_state
+        .device_stop_signals
+        .read()
+        .await
+        .iter()
+        .filter(|(device_id, _)| device_id.as_str() != keep)
+        .map(|(device_id, stop)| (device_id.clone(), stop.clone()))
+        .collect();
+
+    for (device_id, stop) in others {
+        println!("Stopping device {device_id} in favor of user-selected device {keep}");
+        let _ = stop.send(true);
+        tracked_devices.remove(&device_id);
     }
 }
 
-async fn handle_device(adapter: &Adapter, device: &Device, app_state: &AppState) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+// Spawns a `handle_device` notify loop for `device` if it isn't already being tracked.
+// Failed attempts are retried with capped exponential backoff. When a connection ends
+// with its notification stream closing, the backoff resets and the device is retried at
+// once; a connection that ends in an error keeps the current backoff. Retries continue
+// until the device is deliberately stopped (e.g. by a user selecting a different one).
+async fn spawn_device_handler(
+    adapter: &Adapter,
+    device: Device,
+    app_state: &AppState,
+    tracked_devices: &mut HashSet<String>,
+) {
+    let device_id = device.id().to_string();
+    if !tracked_devices.insert(device_id.clone()) {
+        return;
+    }
+
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let stop_tx = Arc::new(stop_tx);
+    app_state.device_stop_signals.write().await.insert(device_id.clone(), stop_tx);
+
+    let adapter = adapter.clone();
+    let app_state = app_state.clone();
+    spawn(async move {
+        let mut stop_rx = stop_rx;
+        let mut backoff = INITIAL_BACKOFF;
+
+        loop {
+            if *stop_rx.borrow() {
+                break;
+            }
+
+            match handle_device(&adapter, &device, &app_state, stop_rx.clone()).await {
+                Ok(DeviceLoopOutcome::Disconnected) => backoff = INITIAL_BACKOFF,
+                Ok(DeviceLoopOutcome::Stopped) => break,
+                Err(err) => {
+                    println!("Connection error for device {device_id}: {err:?}");
+                    set_device_status(&app_state, &device_id, device.name_async().await.ok(), ConnectionState::Disconnected).await;
+                    // Raced against the stop signal so a device stuck in backoff doesn't
+                    // keep retrying for up to a minute after it's told to stop.
+                    tokio::select! {
+                        _ = sleep(backoff) => {}
+                        _ = stop_rx.changed() => break,
+                    }
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+
+        // Single cleanup point so every exit path - stop reported by `handle_device`,
+        // stop request seen at the top of the loop, or stop request seen during
+        // backoff - tears the device down the same way.
+        app_state.device_stop_signals.write().await.remove(&device_id);
+        set_device_status(&app_state, &device_id, device.name_async().await.ok(), ConnectionState::Disconnected).await;
+        let _ = adapter.disconnect_device(&device).await;
+    });
+}
+
+// Why `handle_device`'s notify loop ended, so `spawn_device_handler` can tell a
+// dropped connection (retry with backoff reset) apart from a deliberate stop (exit for good).
+enum DeviceLoopOutcome {
+    Disconnected,
+    Stopped,
+}
+
+async fn handle_device(
+    adapter: &Adapter,
+    device: &Device,
+    app_state: &AppState,
+    mut stop: watch::Receiver<bool>,
+) -> Result<DeviceLoopOutcome, Box<dyn std::error::Error + Send + Sync>> {
+    let device_id = device.id().to_string();
+    let device_name = device.name_async().await.ok();
+
+    if *stop.borrow() {
+        return Ok(DeviceLoopOutcome::Stopped);
+    }
+
+    set_device_status(app_state, &device_id, device_name.clone(), ConnectionState::Connecting).await;
+
     // Connect
     if !device.is_connected().await {
         println!("Connecting device: {}", device.id());
-        adapter.connect_device(&device).await
-            .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
+        tokio::select! {
+            result = adapter.connect_device(&device) => {
+                result.map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
+            }
+            _ = stop.changed() => return Ok(DeviceLoopOutcome::Stopped),
+        }
     }
 
     // Discover services
-    let heart_rate_services = device.discover_services_with_uuid(HRS_UUID).await
-        .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
+    let heart_rate_services = tokio::select! {
+        result = device.discover_services_with_uuid(HRS_UUID) => {
+            result.map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?
+        }
+        _ = stop.changed() => return Ok(DeviceLoopOutcome::Stopped),
+    };
     let heart_rate_service = heart_rate_services
         .first()
         .ok_or("Device should has one heart rate service at least")?;
 
     // Discover
-    let heart_rate_measurements = heart_rate_service
-        .discover_characteristics_with_uuid(HRM_UUID)
-        .await
-        .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
+    let heart_rate_measurements = tokio::select! {
+        result = heart_rate_service.discover_characteristics_with_uuid(HRM_UUID) => {
+            result.map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?
+        }
+        _ = stop.changed() => return Ok(DeviceLoopOutcome::Stopped),
+    };
     let heart_rate_measurement = heart_rate_measurements
         .first()
         .ok_or("HeartRateService should has one heart rate measurement characteristic at least")?;
 
-    let mut updates = heart_rate_measurement.notify().await
-        .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
-    while let Some(Ok(heart_rate)) = updates.next().await {
-        let flag = *heart_rate.get(0).ok_or("No flag")?;
-
-        // Heart Rate Value Format
-        let mut heart_rate_value = *heart_rate.get(1).ok_or("No heart rate u8")? as u16;
-        if flag & 0b00001 != 0 {
-            heart_rate_value |= (*heart_rate.get(2).ok_or("No heart rate u16")? as u16) << 8;
+    let mut updates = tokio::select! {
+        result = heart_rate_measurement.notify() => {
+            result.map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?
         }
+        _ = stop.changed() => return Ok(DeviceLoopOutcome::Stopped),
+    };
+
+    set_device_status(app_state, &device_id, device_name.clone(), ConnectionState::Connected).await;
+    remember_known_device(app_state, device, device_name.clone()).await;
+
+    // Monitor battery level alongside heart rate for as long as this connection lasts
+    let battery_monitor_closed = Arc::new(tokio::sync::Notify::new());
+    {
+        let device = device.clone();
+        let app_state = app_state.clone();
+        let device_id = device_id.clone();
+        let closed = battery_monitor_closed.clone();
+        spawn(async move {
+            if let Err(err) = monitor_battery(&device, &app_state, &device_id, closed).await {
+                println!("Battery monitoring error for device {device_id}: {err:?}");
+            }
+        });
+    }
+    // Belt-and-braces alongside the loop below always exiting via `break`: dropped on
+    // every return from this point on (normal exit, early return, or panic unwind), so
+    // the battery-monitor task can never outlive this connection even if a future
+    // change reintroduces a bare `?` inside the loop.
+    let _battery_monitor_guard = NotifyOnDrop(battery_monitor_closed.clone());
+
+    // Every exit from this loop goes through `break`, not `?`, so the outcome is
+    // returned from one place below, where `_battery_monitor_guard` is dropped and
+    // notifies the battery-monitor task - including on a malformed packet.
+    let outcome = loop {
+        let heart_rate = tokio::select! {
+            update = updates.next() => {
+                match update {
+                    Some(Ok(heart_rate)) => heart_rate,
+                    Some(Err(err)) => break Err(Box::new(err) as Box<dyn std::error::Error + Send + Sync>),
+                    None => break Ok(DeviceLoopOutcome::Disconnected),
+                }
+            }
+            _ = stop.changed() => break Ok(DeviceLoopOutcome::Stopped),
+        };
+
+        let parsed = match parse_heart_rate_packet(&heart_rate) {
+            Ok(parsed) => parsed,
+            Err(err) => break Err(err),
+        };
+
+        let battery_level = app_state.battery_levels.read().await.get(&device_id).copied();
 
-        // Sensor Contact Supported
-        let mut sensor_contact = None;
-        if flag & 0b00100 != 0 {
-            sensor_contact = Some(flag & 0b00010 != 0)
-        }
-        
         let heart_rate_data = HeartRateData {
-            value: heart_rate_value,
-            sensor_contact_detected: sensor_contact,
+            device_id: device_id.clone(),
+            device_name: device_name.clone(),
+            value: parsed.value,
+            sensor_contact_detected: parsed.sensor_contact,
+            energy_expended: parsed.energy_expended,
+            rr_intervals: parsed.rr_intervals.clone(),
+            battery_level,
             timestamp: std::time::SystemTime::now()
                 .duration_since(std::time::UNIX_EPOCH)
                 .unwrap()
                 .as_secs(),
         };
-        
-        println!("HeartRateValue: {heart_rate_value}, SensorContactDetected: {sensor_contact:?}");
+
+        println!("Device: {device_id}, HeartRateValue: {}, SensorContactDetected: {:?}, EnergyExpended: {:?}, RrIntervals: {:?}", parsed.value, parsed.sensor_contact, parsed.energy_expended, parsed.rr_intervals);
+
+        if !parsed.rr_intervals.is_empty() {
+            record_rr_intervals(app_state, &device_id, &parsed.rr_intervals).await;
+        }
 
         // Update global state
         {
             let mut state = app_state.heart_rate_data.write().await;
-            *state = Some(heart_rate_data.clone());
+            state.insert(device_id.clone(), heart_rate_data.clone());
         }
 
         // Send to all WebSocket clients via broadcast channel
         let _ = app_state.tx.send(heart_rate_data);
+    };
+
+    outcome
+}
+
+// Notifies an `Arc<Notify>`'s waiters when dropped, so a task's shutdown signal fires
+// on every exit path from its owning scope - normal return, early `return`, or
+// unwinding - without relying on each one remembering to call `notify_waiters()`.
+struct NotifyOnDrop(Arc<tokio::sync::Notify>);
+
+impl Drop for NotifyOnDrop {
+    fn drop(&mut self) {
+        self.0.notify_waiters();
     }
-    
-    Ok(())
-}
\ No newline at end of file
+}
+
+// A single parsed Heart Rate Measurement notification
+struct ParsedHeartRate {
+    value: u16,
+    sensor_contact: Option<bool>,
+    energy_expended: Option<u16>,
+    rr_intervals: Vec<f32>,
+}
+
+// Parses a Heart Rate Measurement notification per the Bluetooth HRS spec: a flag byte,
+// a 1- or 2-byte heart rate value, and optional energy-expended/RR-interval fields
+// depending on which flag bits are set.
+fn parse_heart_rate_packet(heart_rate: &[u8]) -> Result<ParsedHeartRate, Box<dyn std::error::Error + Send + Sync>> {
+    let flag = *heart_rate.get(0).ok_or("No flag")?;
+    let mut offset = 1usize;
+
+    // Heart Rate Value Format
+    let mut value = *heart_rate.get(offset).ok_or("No heart rate u8")? as u16;
+    offset += 1;
+    if flag & 0b00001 != 0 {
+        value |= (*heart_rate.get(offset).ok_or("No heart rate u16")? as u16) << 8;
+        offset += 1;
+    }
+
+    // Sensor Contact Supported
+    let mut sensor_contact = None;
+    if flag & 0b00100 != 0 {
+        sensor_contact = Some(flag & 0b00010 != 0)
+    }
+
+    // Energy Expended Present
+    let mut energy_expended = None;
+    if flag & 0b01000 != 0 {
+        let low = *heart_rate.get(offset).ok_or("No energy expended low byte")? as u16;
+        let high = *heart_rate.get(offset + 1).ok_or("No energy expended high byte")? as u16;
+        energy_expended = Some(low | (high << 8));
+        offset += 2;
+    }
+
+    // RR-Interval present: every remaining pair of bytes is a little-endian u16 in 1/1024 s
+    let mut rr_intervals = Vec::new();
+    if flag & 0b10000 != 0 {
+        let mut i = offset;
+        while i + 1 < heart_rate.len() {
+            let raw = heart_rate[i] as u16 | ((heart_rate[i + 1] as u16) << 8);
+            rr_intervals.push(raw as f32 * 1000.0 / 1024.0);
+            i += 2;
+        }
+    }
+
+    Ok(ParsedHeartRate { value, sensor_contact, energy_expended, rr_intervals })
+}
+
+// Tracks the Battery Service's level characteristic for `device`, preferring notifications
+// and falling back to a periodic read if the characteristic doesn't support them. Exits when
+// `closed` is notified, which happens once the owning heart rate connection ends.
+async fn monitor_battery(
+    device: &Device,
+    app_state: &AppState,
+    device_id: &str,
+    closed: Arc<tokio::sync::Notify>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let battery_services = device.discover_services_with_uuid(BATTERY_SERVICE_UUID).await
+        .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
+    let Some(battery_service) = battery_services.first() else {
+        return Ok(()); // Device doesn't expose a battery service
+    };
+
+    let battery_level_characteristics = battery_service
+        .discover_characteristics_with_uuid(BATTERY_LEVEL_UUID)
+        .await
+        .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
+    let Some(battery_level_characteristic) = battery_level_characteristics.first().cloned() else {
+        return Ok(()); // Service is present but doesn't expose the level characteristic
+    };
+
+    let result = match battery_level_characteristic.notify().await {
+        Ok(mut updates) => loop {
+            tokio::select! {
+                update = updates.next() => {
+                    match update {
+                        Some(Ok(bytes)) => record_battery_level(app_state, device_id, &bytes).await,
+                        Some(Err(err)) => break Err(Box::new(err) as Box<dyn std::error::Error + Send + Sync>),
+                        None => break Ok(()),
+                    }
+                }
+                _ = closed.notified() => break Ok(()),
+            }
+        },
+        Err(_) => loop {
+            let bytes = match battery_level_characteristic.read().await {
+                Ok(bytes) => bytes,
+                Err(err) => break Err(Box::new(err) as Box<dyn std::error::Error + Send + Sync>),
+            };
+            record_battery_level(app_state, device_id, &bytes).await;
+
+            tokio::select! {
+                _ = sleep(BATTERY_POLL_INTERVAL) => {}
+                _ = closed.notified() => break Ok(()),
+            }
+        },
+    };
+    result
+}
+
+// Stores the latest battery reading for a device and warns once it drops below threshold
+async fn record_battery_level(app_state: &AppState, device_id: &str, bytes: &[u8]) {
+    let Some(&battery_level) = bytes.first() else {
+        return;
+    };
+
+    app_state.battery_levels.write().await.insert(device_id.to_string(), battery_level);
+
+    if let Some(entry) = app_state.heart_rate_data.write().await.get_mut(device_id) {
+        entry.battery_level = Some(battery_level);
+    }
+
+    if battery_level < app_state.battery_warn_threshold {
+        log::warn!("Device {device_id} battery low: {battery_level}%");
+    }
+}